@@ -2,12 +2,59 @@
 // SPDX-License-Identifier: LGPL-3.0
 
 use std::{
-    ffi::{c_ulong, CStr},
+    cell::RefCell,
+    ffi::{c_ulong, CStr, CString},
+    fmt::Debug,
     io,
     os::raw::{c_char, c_int, c_void},
 };
 
-use crate::{BambuSample, LocalSettings, LocalTunnel};
+use crate::{cert_pin, AsyncLocalTunnel, BambuSample, LocalSettings};
+
+thread_local! {
+    /// Last error formatted by a `Bambu_*` call on this thread, surfaced
+    /// through `Bambu_GetLastErrorMsg` so GUI hosts can show something more
+    /// useful than a bare error code. Overwritten by the next failing call.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Formats `err` with `{:?}` and stashes it as this thread's last error, in
+/// addition to the existing `eprintln!` logging.
+fn set_last_error(err: impl Debug) {
+    let message = format!("{err:?}");
+    // CString::new rejects interior NULs; strip them rather than losing the
+    // whole message over a formatting edge case.
+    let message = if message.contains('\0') {
+        message.replace('\0', "")
+    } else {
+        message
+    };
+
+    let c_message = CString::new(message).unwrap_or_default();
+
+    LAST_ERROR.with(|slot| *slot.borrow_mut() = Some(c_message));
+}
+
+/// Opaque FFI handle: pairs the async tunnel with a private current-thread
+/// tokio runtime so `Bambu_*` entry points stay synchronous for C callers
+/// while the tunnel itself never busy-polls.
+pub struct BambuTunnel {
+    tunnel: AsyncLocalTunnel,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl BambuTunnel {
+    fn new(settings: LocalSettings) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()?;
+
+        Ok(Self {
+            tunnel: AsyncLocalTunnel::new(settings),
+            runtime,
+        })
+    }
+}
 
 #[derive(Debug)]
 #[repr(C)]
@@ -28,7 +75,7 @@ const BAMBU_GENERIC_ERROR: c_int = 4;
 
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_Create(
-    handle_out: *mut *mut LocalTunnel,
+    handle_out: *mut *mut BambuTunnel,
     path: *const c_char,
 ) -> c_int {
     let c_str = CStr::from_ptr(path).to_string_lossy();
@@ -37,11 +84,19 @@ pub unsafe extern "C" fn Bambu_Create(
         Ok(settings) => settings,
         Err(e) => {
             eprintln!("TURION: {e}");
+            set_last_error(e);
             return BAMBU_GENERIC_ERROR;
         }
     };
 
-    let internal_tunnel = Box::new(LocalTunnel::new(settings));
+    let internal_tunnel = match BambuTunnel::new(settings) {
+        Ok(internal_tunnel) => Box::new(internal_tunnel),
+        Err(e) => {
+            eprintln!("TURION: {e}");
+            set_last_error(e);
+            return BAMBU_GENERIC_ERROR;
+        }
+    };
 
     *handle_out = Box::leak(internal_tunnel);
 
@@ -49,21 +104,22 @@ pub unsafe extern "C" fn Bambu_Create(
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Bambu_Destroy(handle: *mut LocalTunnel) {
+pub unsafe extern "C" fn Bambu_Destroy(handle: *mut BambuTunnel) {
     /* Recreate and drop the box */
     let _ = Box::from_raw(handle);
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Bambu_Open(handle: *mut LocalTunnel) -> c_int {
+pub unsafe extern "C" fn Bambu_Open(handle: *mut BambuTunnel) -> c_int {
     if handle.is_null() {
         return -1;
     }
 
-    let tunnel = unsafe { &mut *handle };
+    let handle = unsafe { &mut *handle };
 
-    if let Err(e) = tunnel.open() {
+    if let Err(e) = handle.runtime.block_on(handle.tunnel.open()) {
         eprintln!("{e:?}");
+        set_last_error(e);
 
         return -1;
     }
@@ -72,20 +128,21 @@ pub unsafe extern "C" fn Bambu_Open(handle: *mut LocalTunnel) -> c_int {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Bambu_Close(handle: *mut LocalTunnel) {
+pub unsafe extern "C" fn Bambu_Close(handle: *mut BambuTunnel) {
     if handle.is_null() {
         return;
     }
 
-    let tunnel = unsafe { &mut *handle };
+    let handle = unsafe { &mut *handle };
 
-    if let Err(e) = tunnel.close() {
+    if let Err(e) = handle.runtime.block_on(handle.tunnel.close()) {
         eprintln!("{e:?}");
+        set_last_error(e);
     }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Bambu_GetStreamCount(handle: *mut LocalTunnel) -> c_int {
+pub unsafe extern "C" fn Bambu_GetStreamCount(handle: *mut BambuTunnel) -> c_int {
     if handle.is_null() {
         return -1;
     }
@@ -96,7 +153,7 @@ pub unsafe extern "C" fn Bambu_GetStreamCount(handle: *mut LocalTunnel) -> c_int
 
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_GetStreamInfo(
-    handle: *mut LocalTunnel,
+    handle: *mut BambuTunnel,
     _index: i32,
     info: *mut BambuVideoStreamInfo,
 ) -> c_int {
@@ -122,15 +179,16 @@ pub unsafe extern "C" fn Bambu_GetStreamInfo(
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Bambu_StartStreamEx(handle: *mut LocalTunnel, stream_type: i32) -> c_int {
+pub unsafe extern "C" fn Bambu_StartStreamEx(handle: *mut BambuTunnel, stream_type: i32) -> c_int {
     if handle.is_null() {
         return -1;
     }
 
-    let tunnel = unsafe { &mut *handle };
+    let handle = unsafe { &mut *handle };
 
-    if let Err(e) = tunnel.start(stream_type) {
+    if let Err(e) = handle.runtime.block_on(handle.tunnel.start(stream_type)) {
         eprintln!("{e:?}");
+        set_last_error(e);
 
         return -1;
     }
@@ -139,7 +197,7 @@ pub unsafe extern "C" fn Bambu_StartStreamEx(handle: *mut LocalTunnel, stream_ty
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Bambu_StartStream(handle: *mut LocalTunnel, video: bool) -> c_int {
+pub unsafe extern "C" fn Bambu_StartStream(handle: *mut BambuTunnel, video: bool) -> c_int {
     if video {
         Bambu_StartStreamEx(handle, 0x3000)
     } else {
@@ -147,50 +205,133 @@ pub unsafe extern "C" fn Bambu_StartStream(handle: *mut LocalTunnel, video: bool
     }
 }
 
+/// Drops the pinned certificate for the printer identified by `path` (the
+/// same `bambu://` URL passed to [`Bambu_Create`]), so the next connection
+/// re-pins whatever certificate it is presented. Use after replacing a
+/// printer or factory-resetting one that was pinned before.
+#[no_mangle]
+pub unsafe extern "C" fn Bambu_ResetCertPin(path: *const c_char) -> c_int {
+    let c_str = CStr::from_ptr(path).to_string_lossy();
+
+    let settings = match LocalSettings::from_url(&c_str) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("TURION: {e}");
+            set_last_error(e);
+            return BAMBU_GENERIC_ERROR;
+        }
+    };
+
+    let key = cert_pin::pin_key(settings.serial.as_deref(), &settings.hostname);
+
+    if let Err(e) = cert_pin::reset_pin(&key) {
+        eprintln!("TURION: {e}");
+        set_last_error(e);
+        return BAMBU_GENERIC_ERROR;
+    }
+
+    0
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_SendMessage(
-    handle: *mut LocalTunnel,
-    _ctrl: i32,
-    _data: *const u8,
-    _data_len: i32,
+    handle: *mut BambuTunnel,
+    ctrl: i32,
+    data: *const u8,
+    data_len: i32,
 ) -> c_int {
     if handle.is_null() {
         return -1;
     }
 
-    /* TODO: Used for the SD card explorer but "not available on LAN mode" (probably accesible still) */
+    let handle = unsafe { &mut *handle };
+
+    /* A zero-length control message is a valid (request-only) payload, but
+     * from_raw_parts still requires a non-null, aligned pointer even for a
+     * zero-length slice, so don't build it from a possibly-null `data`. */
+    let payload = if data.is_null() || data_len <= 0 {
+        &[]
+    } else {
+        unsafe { core::slice::from_raw_parts(data, data_len as usize) }
+    };
+
+    if let Err(e) = handle
+        .runtime
+        .block_on(handle.tunnel.send_message(ctrl, payload))
+    {
+        eprintln!("TURION: {e:?}");
+        set_last_error(e);
+
+        return -1;
+    }
 
-    -1
+    0
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_RecvMessage(
-    handle: *mut LocalTunnel,
-    _ctrl: *mut i32,
-    _data: *mut u8,
-    _data_len: *mut i32,
+    handle: *mut BambuTunnel,
+    ctrl: *mut i32,
+    data: *mut u8,
+    data_len: *mut i32,
 ) -> c_int {
-    if handle.is_null() {
+    if handle.is_null() || ctrl.is_null() || data_len.is_null() {
         return -1;
     }
 
-    /* TODO: Used for the SD card explorer but "not available on LAN mode" (probably accesible still) */
+    let handle = unsafe { &mut *handle };
+
+    let (msg_ctrl, msg_data) = match handle.tunnel.recv_message() {
+        Ok(msg) => msg,
+        Err(e) => {
+            if let Some(io_error) = e.downcast_ref::<io::Error>() {
+                if io_error.kind() == io::ErrorKind::WouldBlock {
+                    return BAMBU_WOULD_BLOCK_ERROR;
+                }
+            }
+
+            eprintln!("TURION: {e:?}");
+            set_last_error(e);
+
+            return -1;
+        }
+    };
+
+    /* Caller provides the buffer capacity in *data_len; truncate if the
+     * message doesn't fit but still report the real message size. */
+    let capacity = (*data_len).max(0) as usize;
+    let copy_len = msg_data.len().min(capacity);
+
+    /* copy_nonoverlapping requires a non-null, aligned `data` even for a
+     * zero-length copy, so only call it once there is something to copy;
+     * a caller may legitimately pass data = NULL with *data_len = 0. The
+     * message has already been popped from the queue at this point, so such
+     * a call consumes it and only reports its size. */
+    if copy_len > 0 && !data.is_null() {
+        unsafe { core::ptr::copy_nonoverlapping(msg_data.as_ptr(), data, copy_len) };
+    }
+
+    *ctrl = msg_ctrl;
+    *data_len = msg_data.len() as i32;
 
-    -1
+    0
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_ReadSample(
-    handle: *mut LocalTunnel,
+    handle: *mut BambuTunnel,
     sample: *mut BambuSample,
 ) -> c_int {
     if handle.is_null() {
         return -1;
     }
 
-    let tunnel = unsafe { &mut *handle };
+    let handle = unsafe { &mut *handle };
 
-    if let Err(e) = tunnel.read_sample(&mut *sample) {
+    if let Err(e) = handle
+        .runtime
+        .block_on(handle.tunnel.read_sample(&mut *sample))
+    {
         if let Some(io_error) = e.downcast_ref::<io::Error>() {
             if io_error.kind() == io::ErrorKind::WouldBlock
                 || io_error.kind() == io::ErrorKind::Interrupted
@@ -200,6 +341,7 @@ pub unsafe extern "C" fn Bambu_ReadSample(
         }
 
         eprintln!("TURION: {e:?}");
+        set_last_error(e);
 
         return -1;
     }
@@ -209,7 +351,7 @@ pub unsafe extern "C" fn Bambu_ReadSample(
 
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_SetLogger(
-    _handle: *mut LocalTunnel,
+    _handle: *mut BambuTunnel,
     _logger: *const c_void,
     _ctx: *const c_void,
 ) {
@@ -228,16 +370,24 @@ pub unsafe extern "C" fn Bambu_Deinit() {
 
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_GetLastErrorMsg() -> *mut c_char {
-    core::ptr::null_mut()
+    LAST_ERROR.with(|slot| {
+        slot.borrow()
+            .as_ref()
+            .map(|msg| msg.as_ptr() as *mut c_char)
+            .unwrap_or(core::ptr::null_mut())
+    })
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn Bambu_GetDuration(_handle: *mut LocalTunnel) -> c_ulong {
+pub unsafe extern "C" fn Bambu_GetDuration(_handle: *mut BambuTunnel) -> c_ulong {
     // no op
     c_ulong::MAX
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn Bambu_FreeLogMsg(_msg: *const c_char) {
-    // no op
+    /* The pointer returned by Bambu_GetLastErrorMsg is owned by the
+     * thread-local last-error slot, not by the caller, so there is
+     * nothing to free here; it stays valid until overwritten by the
+     * next failing Bambu_* call on this thread. */
 }
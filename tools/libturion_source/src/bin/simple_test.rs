@@ -9,10 +9,15 @@ fn main() {
     let local_settings = LocalSettings::from_url(raw_url.as_str()).unwrap();
     eprintln!("{local_settings:?}");
 
-    let mut tunnel = LocalTunnel::new(local_settings);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
 
-    tunnel.open().unwrap();
-    tunnel.start(0x3000).unwrap();
+    let mut tunnel = AsyncLocalTunnel::new(local_settings);
+
+    runtime.block_on(tunnel.open()).unwrap();
+    runtime.block_on(tunnel.start(0x3000)).unwrap();
 
     let mut sample = BambuSample {
         buffer: std::ptr::null_mut(),
@@ -23,7 +28,7 @@ fn main() {
     };
 
     loop {
-        match tunnel.read_sample(&mut sample) {
+        match runtime.block_on(tunnel.read_sample(&mut sample)) {
             Ok(_) => {
                 eprintln!("Sample: {sample:?}")
             }
@@ -0,0 +1,353 @@
+// Copyright 2025 Mary Guillemard
+// SPDX-License-Identifier: LGPL-3.0
+
+//! Trust-on-first-use certificate pinning.
+//!
+//! Bambu printers present a self-signed certificate, so there is no CA to
+//! validate against and [`crate::NoCertificateVerification`] accepts
+//! anything unconditionally. That leaves a LAN MITM able to transparently
+//! intercept the camera stream. [`PinningCertificateVerification`] instead
+//! records the SHA-256 digest of the leaf certificate seen on the first
+//! successful connect to a given printer and rejects any later connection
+//! presenting a different one.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, Error as TlsError, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+fn config_dir() -> PathBuf {
+    if let Ok(xdg) = std::env::var("XDG_CONFIG_HOME") {
+        if !xdg.is_empty() {
+            return PathBuf::from(xdg);
+        }
+    }
+
+    if let Ok(home) = std::env::var("HOME") {
+        return PathBuf::from(home).join(".config");
+    }
+
+    std::env::temp_dir()
+}
+
+fn store_path() -> PathBuf {
+    config_dir().join("turion").join("pinned_certs.txt")
+}
+
+fn digest_hex(der: &[u8]) -> String {
+    let digest = Sha256::digest(der);
+    let mut out = String::with_capacity(digest.len() * 2);
+    for byte in digest {
+        let _ = write!(out, "{byte:02x}");
+    }
+    out
+}
+
+fn load_store(path: &Path) -> HashMap<String, String> {
+    let Ok(contents) = fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, digest)| (key.to_string(), digest.to_string()))
+        .collect()
+}
+
+fn save_store(path: &Path, store: &HashMap<String, String>) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut contents = String::new();
+    for (key, digest) in store {
+        let _ = writeln!(contents, "{key}={digest}");
+    }
+
+    fs::write(path, contents)
+}
+
+/// Crude cross-process/cross-thread mutex over the pin store at `store_path`:
+/// holds an exclusive lock on a sidecar file (the store path with its
+/// extension replaced by `.lock`) for as long as
+/// it's alive, so a read-modify-write of the store (load, decide, save)
+/// can't interleave with another one and silently drop a just-pinned entry.
+/// `create_new` is atomic at the OS level, which is all the critical section
+/// here (a few bytes of file I/O) needs.
+struct StoreLock {
+    path: PathBuf,
+}
+
+impl StoreLock {
+    fn acquire(store_path: &Path) -> io::Result<Self> {
+        let path = store_path.with_extension("lock");
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        loop {
+            match fs::OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the key a printer's pin is stored under: its serial when known,
+/// falling back to hostname for printers we have no serial for yet.
+pub(crate) fn pin_key(serial: Option<&str>, hostname: &str) -> String {
+    serial.unwrap_or(hostname).to_string()
+}
+
+/// Drops the stored pin for `key`, if any, so the printer can be re-paired
+/// after e.g. being replaced or factory reset.
+pub fn reset_pin(key: &str) -> io::Result<()> {
+    reset_pin_at(&store_path(), key)
+}
+
+fn reset_pin_at(path: &Path, key: &str) -> io::Result<()> {
+    let _lock = StoreLock::acquire(path)?;
+
+    let mut store = load_store(path);
+    store.remove(key);
+    save_store(path, &store)
+}
+
+#[derive(Debug)]
+pub(crate) struct PinningCertificateVerification {
+    provider: CryptoProvider,
+    key: String,
+    store_path: PathBuf,
+}
+
+impl PinningCertificateVerification {
+    pub fn new(provider: CryptoProvider, key: String) -> Self {
+        Self {
+            provider,
+            key,
+            store_path: store_path(),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_store_path(provider: CryptoProvider, key: String, store_path: PathBuf) -> Self {
+        Self {
+            provider,
+            key,
+            store_path,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinningCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let digest = digest_hex(end_entity.as_ref());
+
+        let _lock = StoreLock::acquire(&self.store_path).map_err(|e| {
+            TlsError::General(format!("failed to lock certificate pin store: {e}"))
+        })?;
+        let mut store = load_store(&self.store_path);
+
+        match store.get(&self.key) {
+            Some(pinned) if *pinned == digest => Ok(ServerCertVerified::assertion()),
+            Some(pinned) => Err(TlsError::General(format!(
+                "certificate pin mismatch for {}: expected {pinned}, got {digest} (re-pair with reset_pin if this printer was replaced)",
+                self.key
+            ))),
+            None => {
+                store.insert(self.key.clone(), digest);
+
+                if let Err(e) = save_store(&self.store_path, &store) {
+                    eprintln!("TURION: failed to persist certificate pin for {}: {e}", self.key);
+                }
+
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rustls::crypto::aws_lc_rs as provider;
+
+    use super::*;
+
+    /// Each test gets its own store file under the OS temp dir instead of
+    /// the real `store_path()`, so runs don't race each other or touch the
+    /// caller's actual pinned-cert store.
+    fn scratch_store_path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+        std::env::temp_dir().join(format!(
+            "turion-cert-pin-test-{}-{n}.txt",
+            std::process::id()
+        ))
+    }
+
+    fn verifier(store_path: PathBuf) -> PinningCertificateVerification {
+        PinningCertificateVerification::with_store_path(
+            provider::default_provider(),
+            "test-printer".to_string(),
+            store_path,
+        )
+    }
+
+    fn verify(verifier: &PinningCertificateVerification, cert: &[u8]) -> Result<(), TlsError> {
+        verifier
+            .verify_server_cert(
+                &CertificateDer::from(cert.to_vec()),
+                &[],
+                &ServerName::try_from("printer.local").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn first_connect_pins() {
+        let path = scratch_store_path();
+        let verifier = verifier(path.clone());
+
+        assert!(verify(&verifier, b"leaf-cert-a").is_ok());
+        assert_eq!(
+            load_store(&path).get("test-printer").unwrap(),
+            &digest_hex(b"leaf-cert-a")
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn second_connect_matches() {
+        let path = scratch_store_path();
+        let verifier = verifier(path.clone());
+
+        assert!(verify(&verifier, b"leaf-cert-a").is_ok());
+        assert!(verify(&verifier, b"leaf-cert-a").is_ok());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn second_connect_mismatch_errors() {
+        let path = scratch_store_path();
+        let verifier = verifier(path.clone());
+
+        assert!(verify(&verifier, b"leaf-cert-a").is_ok());
+        assert!(verify(&verifier, b"leaf-cert-b").is_err());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn concurrent_first_connects_for_different_printers_both_persist() {
+        let path = scratch_store_path();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let verifier = PinningCertificateVerification::with_store_path(
+                        provider::default_provider(),
+                        format!("printer-{i}"),
+                        path,
+                    );
+                    let cert = format!("leaf-cert-{i}").into_bytes();
+                    verify(&verifier, &cert).unwrap();
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let store = load_store(&path);
+        for i in 0..8 {
+            assert_eq!(
+                store.get(&format!("printer-{i}")).unwrap(),
+                &digest_hex(format!("leaf-cert-{i}").as_bytes())
+            );
+        }
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reset_then_repin() {
+        let path = scratch_store_path();
+        let verifier = verifier(path.clone());
+
+        assert!(verify(&verifier, b"leaf-cert-a").is_ok());
+        assert!(verify(&verifier, b"leaf-cert-b").is_err());
+
+        reset_pin_at(&path, "test-printer").unwrap();
+
+        assert!(verify(&verifier, b"leaf-cert-b").is_ok());
+        assert_eq!(
+            load_store(&path).get("test-printer").unwrap(),
+            &digest_hex(b"leaf-cert-b")
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -2,11 +2,13 @@
 // SPDX-License-Identifier: LGPL-3.0
 
 mod api;
+mod async_tunnel;
+mod cert_pin;
+
+pub use async_tunnel::AsyncLocalTunnel;
+pub use cert_pin::reset_pin;
 
-use std::io::{self, Error, ErrorKind, Read, Write};
-use std::net::ToSocketAddrs;
 use std::sync::Arc;
-use std::time::Duration;
 
 use anyhow::{bail, Result};
 use rustls::client::danger::HandshakeSignatureValid;
@@ -16,11 +18,29 @@ use rustls::DigitallySignedStruct;
 use rustls::{pki_types::ServerName, RootCertStore};
 use thiserror::Error;
 
-use mio::net::TcpStream;
-
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
-#[error("Local settings parsing: {0}")]
-pub struct LocalSettingsParsingError(&'static str);
+pub enum LocalSettingsParsingError {
+    #[error("invalid schema")]
+    InvalidSchema,
+
+    #[error("invalid url")]
+    InvalidUrl,
+
+    #[error("missing user")]
+    MissingUser,
+
+    #[error("missing passwd")]
+    MissingPassword,
+
+    #[error("missing port")]
+    MissingPort,
+
+    #[error("invalid port: {0}")]
+    InvalidPort(String),
+
+    #[error("bad percent-escape in {field} parameter: {value}")]
+    BadPercentEscape { field: &'static str, value: String },
+}
 
 #[derive(Clone, Debug)]
 pub struct LocalSettings {
@@ -34,24 +54,79 @@ pub struct LocalSettings {
     pub dev_ver: Option<String>,
     pub cli_id: Option<String>,
     pub cli_ver: Option<String>,
+
+    /// Whether to trust-on-first-use pin the printer's certificate.
+    /// Defaults to `true`; set with `pin=0` to fall back to accepting
+    /// whatever certificate the printer presents.
+    pub pin: bool,
 }
 
 const SCHEMA_START: &str = "bambu:///local/";
 
+/// Percent-decodes `value` (plus `+` as space, same as `form_urlencoded`),
+/// reporting which field failed so callers get an actionable error instead
+/// of a silently truncated password.
+fn percent_decode(field: &'static str, value: &str) -> Result<String, LocalSettingsParsingError> {
+    let bad_escape = || LocalSettingsParsingError::BadPercentEscape {
+        field,
+        value: value.to_string(),
+    };
+
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = value.get(i + 1..i + 3).ok_or_else(bad_escape)?;
+                let byte = u8::from_str_radix(hex, 16).map_err(|_| bad_escape())?;
+
+                out.push(byte);
+                i += 3;
+            }
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(out).map_err(|_| bad_escape())
+}
+
+/// Splits `bambu:///local/<host>.?<query>` into the host part (stripping
+/// IPv6 brackets, so a bracketed literal like `[::1]` yields the host `::1`)
+/// and the raw query.
+fn split_host_and_query(part: &str) -> Result<(&str, &str), LocalSettingsParsingError> {
+    if let Some(rest) = part.strip_prefix('[') {
+        let end = rest
+            .find("].?")
+            .ok_or(LocalSettingsParsingError::InvalidUrl)?;
+
+        return Ok((&rest[..end], &rest[end + 3..]));
+    }
+
+    let ip_end = part
+        .find(".?")
+        .ok_or(LocalSettingsParsingError::InvalidUrl)?;
+
+    Ok((&part[0..ip_end], &part[ip_end + 2..]))
+}
+
 impl LocalSettings {
     pub fn from_url(url: &str) -> Result<Self> {
         if !url.starts_with(SCHEMA_START) {
-            bail!(LocalSettingsParsingError("Invalid schema"))
+            bail!(LocalSettingsParsingError::InvalidSchema)
         }
 
         let part = &url[SCHEMA_START.len()..];
-        let ip_end = match part.find(".?") {
-            Some(ip_end) => ip_end,
-            None => bail!(LocalSettingsParsingError("Invalid url")),
-        };
-
-        let hostname: String = part[0..ip_end].to_string();
-        let raw_query = &part[ip_end + 2..];
+        let (hostname, raw_query) = split_host_and_query(part)?;
+        let hostname = hostname.to_string();
 
         let mut username = None;
         let mut password = None;
@@ -61,30 +136,43 @@ impl LocalSettings {
         let mut dev_ver = None;
         let mut cli_id = None;
         let mut cli_ver = None;
+        let mut pin = true;
 
-        for raw_key_val in raw_query.split("&") {
-            let mut parts = raw_key_val.split("=");
-            let key = parts.next().unwrap();
-            let val = parts.next().unwrap();
-
-            match key {
-                "user" => username = Some(val.to_string()),
-                "passwd" => password = Some(val.to_string()),
-                "device" => serial = Some(val.to_string()),
-                "net_ver" => net_ver = Some(val.to_string()),
-                "dev_ver" => dev_ver = Some(val.to_string()),
-                "cli_id" => cli_id = Some(val.to_string()),
-                "cli_ver" => cli_ver = Some(val.to_string()),
-                "port" => port = Some(val.parse::<u16>()?),
+        for raw_key_val in raw_query.split('&') {
+            if raw_key_val.is_empty() {
+                continue;
+            }
+
+            let (raw_key, raw_val) = raw_key_val
+                .split_once('=')
+                .ok_or(LocalSettingsParsingError::InvalidUrl)?;
+            let key = percent_decode("key", raw_key)?;
+
+            match key.as_str() {
+                "user" => username = Some(percent_decode("user", raw_val)?),
+                "passwd" => password = Some(percent_decode("passwd", raw_val)?),
+                "device" => serial = Some(percent_decode("device", raw_val)?),
+                "net_ver" => net_ver = Some(percent_decode("net_ver", raw_val)?),
+                "dev_ver" => dev_ver = Some(percent_decode("dev_ver", raw_val)?),
+                "cli_id" => cli_id = Some(percent_decode("cli_id", raw_val)?),
+                "cli_ver" => cli_ver = Some(percent_decode("cli_ver", raw_val)?),
+                "port" => {
+                    let val = percent_decode("port", raw_val)?;
+                    port = Some(
+                        val.parse::<u16>()
+                            .map_err(|_| LocalSettingsParsingError::InvalidPort(val))?,
+                    );
+                }
+                "pin" => pin = percent_decode("pin", raw_val)? != "0",
                 _ => {
-                    eprintln!("TURION: Unknown parameter {key} ({val})");
+                    eprintln!("TURION: Unknown parameter {key} ({raw_val})");
                 }
             }
         }
 
-        let username = username.unwrap();
-        let password = password.unwrap();
-        let port = port.unwrap();
+        let username = username.ok_or(LocalSettingsParsingError::MissingUser)?;
+        let password = password.ok_or(LocalSettingsParsingError::MissingPassword)?;
+        let port = port.ok_or(LocalSettingsParsingError::MissingPort)?;
 
         Ok(Self {
             hostname,
@@ -96,12 +184,13 @@ impl LocalSettings {
             dev_ver,
             cli_id,
             cli_ver,
+            pin,
         })
     }
 }
 
 #[derive(Debug)]
-struct NoCertificateVerification(CryptoProvider);
+pub(crate) struct NoCertificateVerification(CryptoProvider);
 
 impl NoCertificateVerification {
     pub fn new(provider: CryptoProvider) -> Self {
@@ -144,6 +233,24 @@ impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
     }
 }
 
+/// Builds the `ServerCertVerifier` a connection should use: TOFU pinning
+/// by default, or the blanket [`NoCertificateVerification`] when the caller
+/// opted out via `pin=0`.
+pub(crate) fn make_certificate_verifier(
+    settings: &LocalSettings,
+) -> Arc<dyn rustls::client::danger::ServerCertVerifier> {
+    if settings.pin {
+        let key = cert_pin::pin_key(settings.serial.as_deref(), &settings.hostname);
+
+        Arc::new(cert_pin::PinningCertificateVerification::new(
+            provider::default_provider(),
+            key,
+        ))
+    } else {
+        Arc::new(NoCertificateVerification::new(provider::default_provider()))
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct CameraCmdFrameHeader {
@@ -163,6 +270,26 @@ impl From<[u8; 16]> for CameraCmdFrameHeader {
     }
 }
 
+impl CameraCmdFrameHeader {
+    /// Builds a header for a control-channel frame: `itrack`/`flags` are
+    /// repurposed to carry the control code and its flags, same as the
+    /// video path reuses them for the track index.
+    pub fn new_control(ctrl: i32, flags: i32, frame_len: u32) -> Self {
+        Self {
+            frame_len,
+            itrack: ctrl,
+            flags,
+            padding: 0,
+        }
+    }
+
+    pub const fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const _ as *const u8, core::mem::size_of::<Self>())
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct CameraCmdPacket {
@@ -202,127 +329,7 @@ impl CameraCmdPacket {
 }
 
 #[derive(Debug)]
-pub struct LocalTunnelConnection {
-    poll: mio::Poll,
-    socket: TcpStream,
-    tls_conn: rustls::ClientConnection,
-}
-
-impl io::Write for LocalTunnelConnection {
-    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
-        self.tls_conn.writer().write(bytes)
-    }
-
-    fn flush(&mut self) -> io::Result<()> {
-        self.tls_conn.writer().flush()
-    }
-}
-
-impl io::Read for LocalTunnelConnection {
-    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
-        self.tls_conn.reader().read(bytes)
-    }
-}
-
-impl LocalTunnelConnection {
-    const TOKEN: mio::Token = mio::Token(0);
-
-    fn new(
-        sock: TcpStream,
-        server_name: String,
-        cfg: Arc<rustls::ClientConfig>,
-    ) -> io::Result<Self> {
-        let mut res = Self {
-            poll: mio::Poll::new()?,
-            socket: sock,
-            tls_conn: rustls::ClientConnection::new(cfg, server_name.try_into().unwrap()).unwrap(),
-        };
-
-        let interest = res.event_set();
-        res.poll
-            .registry()
-            .register(&mut res.socket, Self::TOKEN, interest)?;
-
-        Ok(res)
-    }
-
-    fn event_set(&self) -> mio::Interest {
-        let rd = self.tls_conn.wants_read();
-        let wr = self.tls_conn.wants_write();
-
-        if rd && wr {
-            mio::Interest::READABLE | mio::Interest::WRITABLE
-        } else if wr {
-            mio::Interest::WRITABLE
-        } else {
-            mio::Interest::READABLE
-        }
-    }
-
-    fn handshake(&mut self) -> io::Result<()> {
-        let mut events = mio::Events::with_capacity(8);
-        while self.tls_conn.is_handshaking() {
-            loop {
-                match self.poll.poll(&mut events, None) {
-                    Ok(_) => break,
-                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                    Err(e) => return Err(e),
-                }
-            }
-
-            // Register again
-            let interest = self.event_set();
-            self.poll
-                .registry()
-                .reregister(&mut self.socket, Self::TOKEN, interest)?;
-
-            match self.tls_conn.complete_io(&mut self.socket) {
-                Ok(_) => continue,
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                Err(e) => return Err(e),
-            }
-        }
-
-        Ok(())
-    }
-
-    pub fn process_events<R>(&mut self, can_block: bool, mut read_cb: R) -> io::Result<()>
-    where
-        R: FnMut(&mut LocalTunnelConnection) -> io::Result<()>,
-    {
-        let mut events = mio::Events::with_capacity(8);
-
-        let res = loop {
-            // Register again
-            let interest = self.event_set();
-            self.poll
-                .registry()
-                .reregister(&mut self.socket, Self::TOKEN, interest)?;
-
-            match self.poll.poll(&mut events, Some(Duration::from_nanos(10))) {
-                Ok(_) => {}
-                Err(e) if e.kind() == io::ErrorKind::Interrupted && can_block => continue,
-                Err(e) => break Err(e),
-            }
-
-            match self.tls_conn.complete_io(&mut self.socket) {
-                Ok((read_count, write_count)) => break Ok((read_count, write_count)),
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                Err(e) => break Err(e),
-            }
-        };
-
-        res?;
-
-        /* Always assume data as we might have processed something previously and not finish reading */
-        read_cb(self)?;
-
-        Ok(())
-    }
-}
-
-#[derive(Debug)]
-enum LocalTunnelState {
+pub(crate) enum LocalTunnelState {
     Initial,
 
     ProcessStream,
@@ -338,15 +345,6 @@ enum LocalTunnelState {
 #[error("Local tunnel error: {0}")]
 pub struct LocalTunnelError(&'static str);
 
-#[derive(Debug)]
-pub struct LocalTunnel {
-    pub settings: LocalSettings,
-    conn_opt: Option<LocalTunnelConnection>,
-    req_type_opt: Option<i32>,
-    state_opt: Option<LocalTunnelState>,
-    own_sample_buffer: bool,
-}
-
 #[derive(Debug)]
 #[repr(C)]
 pub struct BambuSample {
@@ -388,208 +386,6 @@ impl BambuSample {
     }
 }
 
-impl LocalTunnel {
-    pub const fn new(settings: LocalSettings) -> Self {
-        Self {
-            settings,
-            conn_opt: None,
-            req_type_opt: None,
-            state_opt: None,
-            own_sample_buffer: false,
-        }
-    }
-
-    fn ensure_connected(&self) -> Result<()> {
-        if self.conn_opt.is_none() {
-            bail!(LocalTunnelError("stream not opened"))
-        }
-
-        Ok(())
-    }
-
-    pub fn open(&mut self) -> Result<()> {
-        if self.conn_opt.is_some() {
-            bail!(LocalTunnelError("stream already opened"))
-        }
-
-        if self.state_opt.is_some() {
-            bail!(LocalTunnelError("stream already opened"))
-        }
-
-        let mut cfg =
-            rustls::ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS12])
-                .with_root_certificates(RootCertStore::empty())
-                .with_no_client_auth();
-        let mut dangerous_config = rustls::ClientConfig::dangerous(&mut cfg);
-        dangerous_config.set_certificate_verifier(Arc::new(NoCertificateVerification::new(
-            provider::default_provider(),
-        )));
-
-        let sock_addr = (self.settings.hostname.as_str(), self.settings.port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
-        let sock = TcpStream::connect(sock_addr)?;
-
-        let mut conn =
-            LocalTunnelConnection::new(sock, self.settings.hostname.clone(), Arc::new(cfg))?;
-
-        conn.handshake()?;
-
-        self.conn_opt = Some(conn);
-        self.state_opt = Some(LocalTunnelState::Initial);
-
-        Ok(())
-    }
-
-    pub fn start(&mut self, req_type: i32) -> Result<()> {
-        self.ensure_connected()?;
-
-        let conn = self.conn_opt.as_mut().unwrap();
-
-        match self.state_opt {
-            None | Some(LocalTunnelState::Initial) => {}
-            _ => bail!(LocalTunnelError("stream already started")),
-        }
-
-        let packet = CameraCmdPacket::new(
-            req_type,
-            &self.settings.username,
-            &self.settings.password,
-            true,
-        );
-
-        conn.write_all(packet.as_bytes())?;
-        conn.process_events(true, |_| Ok(()))?;
-
-        self.state_opt = Some(LocalTunnelState::ProcessStream);
-        self.req_type_opt = Some(req_type);
-
-        Ok(())
-    }
-
-    pub fn close(&mut self) -> Result<()> {
-        self.ensure_connected()?;
-
-        let conn = self.conn_opt.as_mut().unwrap();
-
-        match self.state_opt {
-            None | Some(LocalTunnelState::Initial) => bail!(LocalTunnelError("stream not started")),
-            _ => {}
-        }
-
-        let packet = CameraCmdPacket::new(
-            self.req_type_opt.unwrap(),
-            &self.settings.username,
-            &self.settings.password,
-            false,
-        );
-
-        conn.write_all(packet.as_bytes())?;
-        conn.process_events(true, |_| Ok(()))?;
-
-        self.state_opt = Some(LocalTunnelState::Initial);
-
-        Ok(())
-    }
-
-    pub fn read_sample(&mut self, sample: &mut BambuSample) -> Result<()> {
-        self.ensure_connected()?;
-
-        /* Ensure that we have no undefined state on first read...
-         * of course this is highly unsafe but not sure
-         * what we can do better here... */
-        if !self.own_sample_buffer {
-            *sample = BambuSample {
-                buffer: core::ptr::null_mut(),
-                itrack: 0,
-                size: 0,
-                flags: 0,
-                decode_time: 0,
-            };
-
-            self.own_sample_buffer = true;
-        }
-
-        sample.destroy_buffer();
-
-        let conn = self.conn_opt.as_mut().unwrap();
-
-        match &mut self.state_opt {
-            None | Some(LocalTunnelState::Initial) => bail!(LocalTunnelError("stream not started")),
-            Some(LocalTunnelState::ProcessStream) => {
-                let mut switch_state = false;
-
-                let mut raw_header = [0x0u8; 16];
-                let mut data = Vec::new();
-
-                conn.process_events(false, |conn| {
-                    conn.read_exact(&mut raw_header)?;
-                    switch_state = true;
-
-                    Ok(())
-                })?;
-
-                if switch_state {
-                    let header = CameraCmdFrameHeader::from(raw_header);
-                    data.reserve(header.frame_len as _);
-
-                    self.state_opt = Some(LocalTunnelState::ReceivingSample {
-                        header,
-                        remaining_bytes: data.capacity(),
-                        data,
-                    });
-                }
-
-                // We say that we got interrupted to get on the next state
-                bail!(Error::new(ErrorKind::Interrupted, "next state (receiving)"))
-            }
-            Some(LocalTunnelState::ReceivingSample {
-                header,
-                data,
-                remaining_bytes: 0,
-            }) => {
-                sample.set_buffer(*header, data.clone());
-                self.state_opt = Some(LocalTunnelState::ProcessStream);
-            }
-
-            Some(LocalTunnelState::ReceivingSample {
-                header: _,
-                data,
-                remaining_bytes,
-            }) => {
-                conn.process_events(false, |conn| {
-                    let mut buffer = [0u8; 4096];
-
-                    while *remaining_bytes != 0 {
-                        let bufffer_max_len = (*remaining_bytes).min(buffer.len());
-
-                        let n = conn.read(&mut buffer[..bufffer_max_len])?;
-
-                        if n == 0 {
-                            break;
-                        }
-
-                        data.extend_from_slice(&buffer[..n]);
-                        *remaining_bytes -= n;
-                    }
-
-                    /* Should be impossible to get here without some full sample */
-                    assert!(*remaining_bytes == 0);
-
-                    Ok(())
-                })?;
-
-                // We say that we got interrupted to get on the next state
-                bail!(Error::new(ErrorKind::Interrupted, "next state (finishing)"))
-            }
-        }
-
-        Ok(())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -604,4 +400,31 @@ mod tests {
         assert_eq!(local_settings.username, "elysia");
         assert_eq!(local_settings.password, "ego");
     }
+
+    #[test]
+    fn test_percent_encoded_passwd_roundtrips() {
+        let local_settings = LocalSettings::from_url(
+            "bambu:///local/127.0.0.1.?port=1234&user=elysia&passwd=a%26b%3Dc",
+        )
+        .unwrap();
+        assert_eq!(local_settings.password, "a&b=c");
+    }
+
+    #[test]
+    fn test_ipv6_host() {
+        let local_settings =
+            LocalSettings::from_url("bambu:///local/[::1].?port=1234&user=elysia&passwd=ego")
+                .unwrap();
+        assert_eq!(local_settings.hostname, "::1");
+    }
+
+    #[test]
+    fn test_missing_field_is_descriptive_error() {
+        let err = LocalSettings::from_url("bambu:///local/127.0.0.1.?port=1234&passwd=ego")
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<LocalSettingsParsingError>().unwrap(),
+            LocalSettingsParsingError::MissingUser
+        );
+    }
 }